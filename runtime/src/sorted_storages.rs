@@ -2,11 +2,93 @@ use crate::accounts_db::SnapshotStorage;
 use log::*;
 use solana_measure::measure::Measure;
 use solana_sdk::clock::Slot;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
 use std::ops::Range;
 
+// Below this ratio of slot_count to range_width, a dense Vec would spend most of its
+// space on empty slots, so we switch to a sparse, map-based backing store instead.
+const SPARSE_STORAGES_DENSITY_THRESHOLD: f64 = 0.5;
+
+enum SortedStoragesBacking<'a> {
+    Dense(Vec<Option<&'a SnapshotStorage>>),
+    Sparse(BTreeMap<Slot, &'a SnapshotStorage>),
+}
+
+impl<'a> SortedStoragesBacking<'a> {
+    fn get(&self, range_start: Slot, slot: Slot) -> Option<&'a SnapshotStorage> {
+        match self {
+            Self::Dense(storages) => {
+                let index = (slot - range_start) as usize;
+                storages[index]
+            }
+            Self::Sparse(storages) => storages.get(&slot).copied(),
+        }
+    }
+
+    fn iter_range(&self, range_start: Slot, range: Range<Slot>) -> SortedStoragesBackingIter<'a, '_> {
+        match self {
+            Self::Dense(storages) => SortedStoragesBackingIter::Dense {
+                slot: range.start,
+                end: range.end,
+                range_start,
+                storages,
+            },
+            Self::Sparse(storages) => SortedStoragesBackingIter::Sparse(storages.range(range)),
+        }
+    }
+
+    // length used only in tests: for Dense this is the range width (as the old dense
+    // Vec's len() was); for Sparse it is the number of populated slots
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        match self {
+            Self::Dense(storages) => storages.len(),
+            Self::Sparse(storages) => storages.len(),
+        }
+    }
+}
+
+// 'a is the lifetime of the underlying SnapshotStorage references (the Item type);
+// 'b is the lifetime of the borrow of the backing store being iterated over.
+enum SortedStoragesBackingIter<'a, 'b> {
+    Dense {
+        slot: Slot,
+        end: Slot,
+        range_start: Slot,
+        storages: &'b [Option<&'a SnapshotStorage>],
+    },
+    Sparse(std::collections::btree_map::Range<'b, Slot, &'a SnapshotStorage>),
+}
+
+impl<'a, 'b> Iterator for SortedStoragesBackingIter<'a, 'b> {
+    type Item = (Slot, &'a SnapshotStorage);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Self::Dense {
+                slot,
+                end,
+                range_start,
+                storages,
+            } => {
+                while *slot < *end {
+                    let current = *slot;
+                    *slot += 1;
+                    if let Some(storage) = storages[(current - *range_start) as usize] {
+                        return Some((current, storage));
+                    }
+                }
+                None
+            }
+            Self::Sparse(storages) => storages.next().map(|(slot, storage)| (*slot, *storage)),
+        }
+    }
+}
+
 pub struct SortedStorages<'a> {
     range: Range<Slot>,
-    storages: Vec<Option<&'a SnapshotStorage>>,
+    storages: SortedStoragesBacking<'a>,
     slot_count: usize,
 }
 
@@ -15,8 +97,7 @@ impl<'a> SortedStorages<'a> {
         if !self.range.contains(&slot) {
             None
         } else {
-            let index = (slot - self.range.start) as usize;
-            self.storages[index]
+            self.storages.get(self.range.start, slot)
         }
     }
 
@@ -32,6 +113,20 @@ impl<'a> SortedStorages<'a> {
         self.slot_count
     }
 
+    /// Iterate over every populated (slot, storage) pair in ascending slot order.
+    /// Unlike walking `range()` and calling `get`, this skips empty slots, so the
+    /// cost is proportional to `slot_count()` rather than `range_width()`.
+    pub fn iter(&self) -> impl Iterator<Item = (Slot, &'a SnapshotStorage)> + '_ {
+        self.iter_range(self.range.clone())
+    }
+
+    /// Like `iter`, but restricted to `range` (clamped to this instance's own range).
+    pub fn iter_range(&self, range: Range<Slot>) -> impl Iterator<Item = (Slot, &'a SnapshotStorage)> + '_ {
+        let start = range.start.max(self.range.start);
+        let end = range.end.min(self.range.end).max(start);
+        self.storages.iter_range(self.range.start, start..end)
+    }
+
     // assumptions:
     // 1. each SnapshotStorage.!is_empty()
     // 2. SnapshotStorage.first().unwrap().get_slot() is unique from all other SnapshotStorage items.
@@ -58,40 +153,110 @@ impl<'a> SortedStorages<'a> {
             slots.len(),
             "source and slots are different lengths"
         );
-        let mut min = Slot::MAX;
-        let mut max = Slot::MIN;
-        let slot_count = source.len();
-        let mut time = Measure::start("get slot");
-        slots.iter().for_each(|slot| {
-            let slot = *slot;
-            min = std::cmp::min(slot, min);
-            max = std::cmp::max(slot + 1, max);
+        let mut time = Measure::start("sort");
+        let mut entries = source
+            .iter()
+            .zip(slots.iter())
+            .map(|(storage, slot)| (*slot, storage))
+            .collect::<Vec<_>>();
+        entries.sort_unstable_by_key(|(slot, _)| *slot);
+        entries.windows(2).for_each(|window| {
+            assert!(window[0].0 != window[1].0, "slots are not unique");
         });
         time.stop();
-        let mut time2 = Measure::start("sort");
-        let range;
-        let mut storages;
-        if min > max {
-            range = Range::default();
-            storages = vec![];
+        debug!("SortedStorages, sort time: {}", time.as_us());
+        Self::from_sorted_entries(entries)
+    }
+
+    /// Add `source[i]` at `slots[i]` to this set of storages, re-deriving the range and
+    /// `slot_count` to cover the union. Panics if any new slot collides with an existing one.
+    ///
+    /// The existing entries are not re-sorted: only the new batch is sorted (it is
+    /// typically much smaller than the existing set), then linearly merged against the
+    /// already-sorted existing entries, so cost is
+    /// O(slot_count + new_slots * log(new_slots)) rather than
+    /// O((slot_count + new_slots) * log(slot_count + new_slots)).
+    pub fn extend(&mut self, source: &'a [SnapshotStorage], slots: &[Slot]) {
+        assert_eq!(
+            source.len(),
+            slots.len(),
+            "source and slots are different lengths"
+        );
+        let mut new_entries = source
+            .iter()
+            .zip(slots.iter())
+            .map(|(storage, slot)| (*slot, storage))
+            .collect::<Vec<_>>();
+        new_entries.sort_unstable_by_key(|(slot, _)| *slot);
+        new_entries.windows(2).for_each(|window| {
+            assert!(window[0].0 != window[1].0, "slots are not unique");
+        });
+        let merged = Self::merge_sorted(self.iter(), new_entries.into_iter());
+        *self = Self::from_sorted_entries(merged);
+    }
+
+    /// Combine two `SortedStorages` into a single instance covering the union of their
+    /// ranges. Panics if `a` and `b` share any slot. Since both inputs are already sorted
+    /// by slot, this is a linear merge (O(a.slot_count() + b.slot_count())) rather than a
+    /// full re-sort of the combined entries.
+    pub fn merge(a: &SortedStorages<'a>, b: &SortedStorages<'a>) -> SortedStorages<'a> {
+        let merged = Self::merge_sorted(a.iter(), b.iter());
+        Self::from_sorted_entries(merged)
+    }
+
+    // merge two slot-ascending, slot-unique sequences into one slot-ascending sequence;
+    // panics if the same slot appears in both `a` and `b`
+    fn merge_sorted(
+        a: impl Iterator<Item = (Slot, &'a SnapshotStorage)>,
+        b: impl Iterator<Item = (Slot, &'a SnapshotStorage)>,
+    ) -> Vec<(Slot, &'a SnapshotStorage)> {
+        let mut a = a.peekable();
+        let mut b = b.peekable();
+        let mut merged = Vec::with_capacity(a.size_hint().0 + b.size_hint().0);
+        loop {
+            match (a.peek(), b.peek()) {
+                (Some((slot_a, _)), Some((slot_b, _))) => match slot_a.cmp(slot_b) {
+                    Ordering::Less => merged.push(a.next().unwrap()),
+                    Ordering::Greater => merged.push(b.next().unwrap()),
+                    Ordering::Equal => panic!("slots are not unique"),
+                },
+                (Some(_), None) => merged.push(a.next().unwrap()),
+                (None, Some(_)) => merged.push(b.next().unwrap()),
+                (None, None) => break,
+            }
+        }
+        merged
+    }
+
+    // entries must already be sorted ascending by slot with no duplicates; picks the
+    // backing representation (dense vs sparse) based on how densely packed the slots are
+    fn from_sorted_entries(entries: Vec<(Slot, &'a SnapshotStorage)>) -> Self {
+        let slot_count = entries.len();
+        let mut time = Measure::start("build backing");
+        let (range, storages) = if entries.is_empty() {
+            (Range::default(), SortedStoragesBacking::Dense(vec![]))
         } else {
-            range = Range {
+            let min = entries.first().unwrap().0;
+            let max = entries.last().unwrap().0 + 1;
+            let range = Range {
                 start: min,
                 end: max,
             };
-            let len = max - min;
-            storages = vec![None; len as usize];
-            source
-                .iter()
-                .zip(slots)
-                .for_each(|(original_storages, slot)| {
-                    let index = (slot - min) as usize;
-                    assert!(storages[index].is_none(), "slots are not unique"); // we should not encounter the same slot twice
-                    storages[index] = Some(original_storages);
+            let range_width = max - min;
+            let density = slot_count as f64 / range_width as f64;
+            let backing = if density >= SPARSE_STORAGES_DENSITY_THRESHOLD {
+                let mut dense = vec![None; range_width as usize];
+                entries.into_iter().for_each(|(slot, storage)| {
+                    dense[(slot - min) as usize] = Some(storage);
                 });
-        }
-        time2.stop();
-        debug!("SortedStorages, times: {}, {}", time.as_us(), time2.as_us());
+                SortedStoragesBacking::Dense(dense)
+            } else {
+                SortedStoragesBacking::Sparse(entries.into_iter().collect())
+            };
+            (range, backing)
+        };
+        time.stop();
+        debug!("SortedStorages, build backing time: {}", time.as_us());
         Self {
             range,
             storages,
@@ -117,7 +282,7 @@ pub mod tests {
 
             Self {
                 range,
-                storages,
+                storages: SortedStoragesBacking::Dense(storages),
                 slot_count,
             }
         }
@@ -193,4 +358,134 @@ pub mod tests {
         assert_eq!(result.get(slots[0]).unwrap().len(), vec_check.len());
         assert_eq!(result.get(slots[1]).unwrap().len(), vec_check.len());
     }
+
+    #[test]
+    fn test_sorted_storages_sparse_backing() {
+        let vec = vec![];
+        let vec_check = vec.clone();
+        let slots = [4, 10_004]; // range_width = 10_001, density well below the threshold
+        let vecs = [vec.clone(), vec];
+        let result = SortedStorages::new_with_slots(&vecs, &slots);
+        assert!(matches!(result.storages, SortedStoragesBacking::Sparse(_)));
+        assert_eq!(
+            result.range,
+            Range {
+                start: slots[0],
+                end: slots[1] + 1,
+            }
+        );
+        assert_eq!(result.slot_count, 2);
+        assert!(result.get(0).is_none());
+        assert!(result.get(5_000).is_none());
+        assert_eq!(result.get(slots[0]).unwrap().len(), vec_check.len());
+        assert_eq!(result.get(slots[1]).unwrap().len(), vec_check.len());
+    }
+
+    #[test]
+    fn test_sorted_storages_iter() {
+        let vecs = [vec![], vec![]];
+        let slots = [4, 7];
+        let result = SortedStorages::new_with_slots(&vecs, &slots);
+        let found = result.iter().map(|(slot, _)| slot).collect::<Vec<_>>();
+        assert_eq!(found, slots);
+    }
+
+    #[test]
+    fn test_sorted_storages_iter_range() {
+        let vecs = [vec![], vec![], vec![]];
+        let slots = [4, 7, 9];
+        let result = SortedStorages::new_with_slots(&vecs, &slots);
+        let found = result
+            .iter_range(5..9)
+            .map(|(slot, _)| slot)
+            .collect::<Vec<_>>();
+        assert_eq!(found, vec![7]);
+        assert_eq!(result.iter_range(0..100).count(), 3);
+        assert_eq!(result.iter_range(100..200).count(), 0);
+    }
+
+    #[test]
+    fn test_sorted_storages_iter_sparse_backing() {
+        let vecs = [vec![], vec![], vec![]];
+        let slots = [4, 5_004, 10_004]; // range_width = 10_001, density well below the threshold
+        let result = SortedStorages::new_with_slots(&vecs, &slots);
+        assert!(matches!(result.storages, SortedStoragesBacking::Sparse(_)));
+
+        let found = result.iter().map(|(slot, _)| slot).collect::<Vec<_>>();
+        assert_eq!(found, slots);
+
+        let found = result
+            .iter_range(5..5_005)
+            .map(|(slot, _)| slot)
+            .collect::<Vec<_>>();
+        assert_eq!(found, vec![5_004]);
+        assert_eq!(result.iter_range(0..100_000).count(), 3);
+        assert_eq!(result.iter_range(100_000..200_000).count(), 0);
+    }
+
+    #[test]
+    fn test_sorted_storages_extend() {
+        let base_vecs = [vec![], vec![]];
+        let base_slots = [4, 7];
+        let mut result = SortedStorages::new_with_slots(&base_vecs, &base_slots);
+
+        let more_vecs = [vec![]];
+        let more_slots = [100];
+        result.extend(&more_vecs, &more_slots);
+
+        // the new slot is far enough away that the merged result should be Sparse
+        assert!(matches!(result.storages, SortedStoragesBacking::Sparse(_)));
+        assert_eq!(result.slot_count(), 3);
+        assert_eq!(*result.range(), 4..101);
+        assert!(result.get(4).is_some());
+        assert!(result.get(7).is_some());
+        assert!(result.get(100).is_some());
+        assert!(result.get(50).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "slots are not unique")]
+    fn test_sorted_storages_extend_duplicate_slot() {
+        let base_vecs = [vec![]];
+        let base_slots = [4];
+        let mut result = SortedStorages::new_with_slots(&base_vecs, &base_slots);
+
+        let more_vecs = [vec![]];
+        let more_slots = [4];
+        result.extend(&more_vecs, &more_slots);
+    }
+
+    #[test]
+    fn test_sorted_storages_merge() {
+        let a_vecs = [vec![], vec![]];
+        let a_slots = [4, 7];
+        let a = SortedStorages::new_with_slots(&a_vecs, &a_slots);
+
+        let b_vecs = [vec![]];
+        let b_slots = [100];
+        let b = SortedStorages::new_with_slots(&b_vecs, &b_slots);
+
+        let merged = SortedStorages::merge(&a, &b);
+        // a and b are far enough apart that the merged result should be Sparse
+        assert!(matches!(merged.storages, SortedStoragesBacking::Sparse(_)));
+        assert_eq!(merged.slot_count(), 3);
+        assert_eq!(*merged.range(), 4..101);
+        assert!(merged.get(4).is_some());
+        assert!(merged.get(7).is_some());
+        assert!(merged.get(100).is_some());
+    }
+
+    #[test]
+    #[should_panic(expected = "slots are not unique")]
+    fn test_sorted_storages_merge_duplicate_slot() {
+        let a_vecs = [vec![]];
+        let a_slots = [4];
+        let a = SortedStorages::new_with_slots(&a_vecs, &a_slots);
+
+        let b_vecs = [vec![]];
+        let b_slots = [4];
+        let b = SortedStorages::new_with_slots(&b_vecs, &b_slots);
+
+        SortedStorages::merge(&a, &b);
+    }
 }